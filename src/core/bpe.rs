@@ -18,7 +18,8 @@
 //!
 //! - **Time**: O(N × M) where N is text length and M is number of merges
 //!   - Initialization: O(N) to create nodes
-//!   - Each merge: O(N) to find minimum rank (linear scan)
+//!   - Each merge: O(N) to find minimum rank for short pieces (linear scan),
+//!     or O(log N) for long pieces via the lazy-deletion binary heap
 //!   - Total merges: O(M) where M ≤ N-1
 //!   - Average case with good vocabularies: O(N log N)
 //!
@@ -33,7 +34,15 @@
 //! 5. Update affected neighbor ranks
 //! 6. Repeat until no merges possible
 
+use rayon::prelude::*;
 use rustc_hash::FxHashMap;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// Pieces at least this long use the heap-driven selection path instead of the
+/// linear min-rank scan. Below it the linear scan wins: the scan itself is
+/// cheap and we avoid the heap's allocation and bookkeeping overhead.
+const HEAP_THRESHOLD: usize = 256;
 
 /// A node in the doubly-linked list used for BPE merging.
 ///
@@ -51,6 +60,10 @@ struct Node {
     start: usize,
     /// Length of this piece in bytes
     len: usize,
+    /// Monotonic counter bumped whenever `rank` changes or the node is consumed
+    /// by a merge. Heap entries carry the version they were pushed with so the
+    /// selection loop can discard stale (outdated or tombstoned) entries.
+    version: u32,
 }
 
 /// Perform byte-pair encoding on a piece of text using a linked-list approach.
@@ -65,18 +78,204 @@ struct Node {
 /// The linked-list approach has O(N) complexity per merge instead of O(N)
 /// memory copying that vector-based approaches require.
 pub fn byte_pair_encode(piece: &[u8], encoder: &FxHashMap<Vec<u8>, u32>) -> Vec<u32> {
+    // Dispatch pathologically large pieces to the bounded-memory recursive path.
+    // `DEFAULT_LARGE_PIECE_THRESHOLD` sits far above any real word, so ordinary
+    // tokenization never takes this branch and its output is unchanged.
+    if piece.len() > DEFAULT_LARGE_PIECE_THRESHOLD {
+        return byte_pair_encode_large(piece, encoder, DEFAULT_LARGE_PIECE_THRESHOLD);
+    }
+    byte_pair_encode_core(piece, encoder)
+}
+
+/// Project the merged segments of `piece` into ranks.
+///
+/// This is the standard (non-recursive) encode path shared by
+/// [`byte_pair_encode`] and the base case of [`byte_pair_encode_large`]; it
+/// never re-dispatches to the large path, so recursion always terminates.
+fn byte_pair_encode_core(piece: &[u8], encoder: &FxHashMap<Vec<u8>, u32>) -> Vec<u32> {
+    // Project the merged segments into ranks, falling back to per-byte encoding
+    // for any segment the vocabulary somehow does not cover.
+    let mut result = Vec::new();
+    for (start, len) in byte_pair_merge(piece, encoder) {
+        let slice = &piece[start..start + len];
+        if let Some(&rank) = encoder.get(slice) {
+            result.push(rank);
+        } else {
+            // Fallback: if somehow we have an unknown token, try to encode bytes individually
+            // This shouldn't happen with a proper BPE vocabulary that covers all bytes
+            for &byte in slice {
+                if let Some(&rank) = encoder.get(&[byte][..]) {
+                    result.push(rank);
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Default piece length above which [`byte_pair_encode_large`] switches to the
+/// recursive divide-and-conquer path. Chosen to sit well above any real word
+/// but below the ~20k-char inputs where the greedy merge starts to show
+/// superlinear memory blowup.
+pub const DEFAULT_LARGE_PIECE_THRESHOLD: usize = 20_000;
+
+/// Maximum recursion depth for [`byte_pair_encode_large`]. Once hit, the
+/// current sub-piece is handed to the standard loop regardless of its length.
+const MAX_LARGE_RECURSION_DEPTH: u32 = 16;
+
+/// Encode a piece that may be pathologically large (base64 blobs, concatenated
+/// words) with bounded memory.
+///
+/// For very long pieces even the O(N log N) heap merge holds the whole node
+/// list in memory at once; tiktoken's big-token work observed superlinear
+/// blowups on ~20k-char inputs. When `piece.len()` exceeds `threshold` this
+/// splits the piece at the weakest (highest-rank) boundary near its midpoint (see
+/// [`large_split_point`]), recurses on the two halves, and stitches the rank
+/// vectors together. Below the threshold — and at the recursion-depth cap — it
+/// defers to the standard [`byte_pair_encode_core`] loop.
+///
+/// [`byte_pair_encode`] dispatches here automatically for pieces longer than
+/// [`DEFAULT_LARGE_PIECE_THRESHOLD`]; call this directly to use a custom
+/// threshold.
+///
+/// Note: splitting before merging trades exact greedy-merge output equivalence
+/// for bounded memory on degenerate inputs. The split point is chosen to sever
+/// the weakest (highest-rank) adjacent boundary, but a longer token that spans
+/// the chosen boundary may still not be formed.
+pub fn byte_pair_encode_large(
+    piece: &[u8],
+    encoder: &FxHashMap<Vec<u8>, u32>,
+    threshold: usize,
+) -> Vec<u32> {
+    encode_large_inner(piece, encoder, threshold, MAX_LARGE_RECURSION_DEPTH)
+}
+
+fn encode_large_inner(
+    piece: &[u8],
+    encoder: &FxHashMap<Vec<u8>, u32>,
+    threshold: usize,
+    depth: u32,
+) -> Vec<u32> {
+    // Base case: small enough, or we have recursed as deep as allowed. Call the
+    // core path directly so we never re-dispatch back into this function.
+    if depth == 0 || piece.len() <= threshold {
+        return byte_pair_encode_core(piece, encoder);
+    }
+
+    let split = large_split_point(piece, encoder);
+    let mut result = encode_large_inner(&piece[..split], encoder, threshold, depth - 1);
+    result.extend(encode_large_inner(&piece[split..], encoder, threshold, depth - 1));
+    result
+}
+
+/// Pick a split index for a large piece at the weakest adjacent boundary.
+///
+/// Examines boundaries in a window around the midpoint and picks the one whose
+/// straddling byte pair has the *highest* rank — i.e. the merge the greedy
+/// algorithm would perform last, or (best of all) an unmergeable pair the
+/// vocabulary has no entry for (`u32::MAX`). Cutting at the weakest boundary
+/// minimizes the chance of severing a token that would otherwise form. Ties
+/// resolve to the boundary nearest the midpoint to keep both halves balanced.
+///
+/// The window is bounded to a quarter of the piece on either side of the
+/// midpoint so each half retains at least a quarter of the input and recursion
+/// makes steady progress.
+fn large_split_point(piece: &[u8], encoder: &FxHashMap<Vec<u8>, u32>) -> usize {
+    let mid = piece.len() / 2;
+    let radius = (piece.len() / 4).max(1);
+    let lo = mid.saturating_sub(radius).max(1);
+    let hi = (mid + radius).min(piece.len() - 1);
+
+    // Rank of the byte pair straddling boundary `i` (`u32::MAX` if unmergeable).
+    let pair_rank = |i: usize| encoder.get(&piece[i - 1..i + 1]).copied().unwrap_or(u32::MAX);
+
+    let mut best = mid;
+    let mut best_rank = pair_rank(mid);
+    for i in lo..=hi {
+        let rank = pair_rank(i);
+        // Prefer a higher rank (weaker merge); on a tie prefer the boundary
+        // closer to the midpoint.
+        if rank > best_rank
+            || (rank == best_rank && i.abs_diff(mid) < best.abs_diff(mid))
+        {
+            best = i;
+            best_rank = rank;
+        }
+    }
+
+    best
+}
+
+/// Batches smaller than this are encoded serially; spinning up the rayon
+/// thread pool is not worth it until there is enough work to amortize.
+const BATCH_PARALLEL_THRESHOLD: usize = 32;
+
+/// Encode many independent pieces in parallel.
+///
+/// Each [`byte_pair_encode`] call is independent and only reads the shared
+/// `encoder`, so the pieces map cleanly onto a rayon parallel iterator over a
+/// work-stealing thread pool. Small batches fall back to a serial loop to avoid
+/// paying thread-pool overhead on trivial inputs; the single-piece API is
+/// unchanged.
+pub fn byte_pair_encode_batch(
+    pieces: &[&[u8]],
+    encoder: &FxHashMap<Vec<u8>, u32>,
+) -> Vec<Vec<u32>> {
+    if pieces.len() < BATCH_PARALLEL_THRESHOLD {
+        return pieces
+            .iter()
+            .map(|piece| byte_pair_encode(piece, encoder))
+            .collect();
+    }
+
+    pieces
+        .par_iter()
+        .map(|piece| byte_pair_encode(piece, encoder))
+        .collect()
+}
+
+/// Split `piece` into the final merged substrings produced by BPE.
+///
+/// This is the split/merge decomposition that sits alongside
+/// [`byte_pair_encode`]: instead of projecting the merged segments into ranks
+/// it yields them as byte slices borrowed from `piece`. It is useful for
+/// vocabulary inspection, token-boundary visualization, and debugging why a
+/// given word tokenizes the way it does. Both functions share the same merge
+/// routine ([`byte_pair_merge`]), so for pieces of up to
+/// [`DEFAULT_LARGE_PIECE_THRESHOLD`] bytes their boundaries are the same.
+/// Longer pieces are encoded by [`byte_pair_encode`] through
+/// [`byte_pair_encode_large`], which splits them before merging; this function
+/// does not, so for such pieces its slices can differ from the encoded tokens.
+pub fn byte_pair_split<'a>(
+    piece: &'a [u8],
+    encoder: &FxHashMap<Vec<u8>, u32>,
+) -> Vec<&'a [u8]> {
+    byte_pair_merge(piece, encoder)
+        .into_iter()
+        .map(|(start, len)| &piece[start..start + len])
+        .collect()
+}
+
+/// Run the linked-list merge and return the final pieces as `(start, len)`
+/// segments into `piece`.
+///
+/// This is the shared core of [`byte_pair_encode_core`] and
+/// [`byte_pair_split`]: both project this segment list into their respective
+/// outputs. [`byte_pair_encode`] uses it directly only for pieces of up to
+/// [`DEFAULT_LARGE_PIECE_THRESHOLD`] bytes.
+fn byte_pair_merge(piece: &[u8], encoder: &FxHashMap<Vec<u8>, u32>) -> Vec<(usize, usize)> {
     if piece.is_empty() {
         return vec![];
     }
 
     // Fast path: single byte
     if piece.len() == 1 {
-        return encoder.get(piece).copied().map_or(vec![], |r| vec![r]);
+        return vec![(0, 1)];
     }
 
     // Fast path: entire piece is a single token
-    if let Some(&rank) = encoder.get(piece) {
-        return vec![rank];
+    if encoder.contains_key(piece) {
+        return vec![(0, piece.len())];
     }
 
     // Initialize linked list - one node per byte
@@ -92,6 +291,7 @@ pub fn byte_pair_encode(piece: &[u8], encoder: &FxHashMap<Vec<u8>, u32>) -> Vec<
             rank: u32::MAX,
             start: i,
             len: 1,
+            version: 0,
         });
     }
 
@@ -115,34 +315,12 @@ pub fn byte_pair_encode(piece: &[u8], encoder: &FxHashMap<Vec<u8>, u32>) -> Vec<
         nodes[i].rank = get_rank(i, nodes[i].next, &nodes);
     }
 
-    // Main merge loop
-    loop {
-        // Find the pair with minimum rank (highest priority merge)
-        let mut min_rank = u32::MAX;
-        let mut min_idx = usize::MAX;
-
-        let mut curr = 0;
-        // Find the head of the list (in case we started from a deleted node)
-        while nodes[curr].prev != usize::MAX {
-            curr = nodes[curr].prev;
-        }
-
-        // Linear scan through the linked list
-        while curr != usize::MAX {
-            let r = nodes[curr].rank;
-            if r < min_rank {
-                min_rank = r;
-                min_idx = curr;
-            }
-            curr = nodes[curr].next;
-        }
-
-        // No more merges possible
-        if min_rank == u32::MAX {
-            break;
-        }
-
-        // Merge min_idx with its next node
+    // Perform a single merge of `min_idx` with its successor, updating pointers
+    // and the ranks of the two affected pairs, which start at `prev` and at
+    // `min_idx`. Returns `(prev, new_next)` — the merged node's new neighbours —
+    // so the caller can refresh whatever bookkeeping its selection strategy
+    // relies on.
+    let apply_merge = |min_idx: usize, nodes: &mut Vec<Node>| -> (usize, usize) {
         let next_idx = nodes[min_idx].next;
 
         // Update the merged node's length
@@ -155,19 +333,91 @@ pub fn byte_pair_encode(piece: &[u8], encoder: &FxHashMap<Vec<u8>, u32>) -> Vec<
             nodes[new_next].prev = min_idx;
         }
 
+        // Tombstone the consumed node so stale heap entries referencing it are
+        // rejected on pop.
+        nodes[next_idx].version = nodes[next_idx].version.wrapping_add(1);
+
         // Update ranks for affected pairs:
         // 1. The pair (prev, min_idx) if prev exists
-        if nodes[min_idx].prev != usize::MAX {
-            let prev = nodes[min_idx].prev;
-            nodes[prev].rank = get_rank(prev, min_idx, &nodes);
+        let prev = nodes[min_idx].prev;
+        if prev != usize::MAX {
+            nodes[prev].rank = get_rank(prev, min_idx, nodes);
+            nodes[prev].version = nodes[prev].version.wrapping_add(1);
         }
 
         // 2. The pair (min_idx, new_next)
-        nodes[min_idx].rank = get_rank(min_idx, nodes[min_idx].next, &nodes);
+        nodes[min_idx].rank = get_rank(min_idx, new_next, nodes);
+        nodes[min_idx].version = nodes[min_idx].version.wrapping_add(1);
+
+        (prev, new_next)
+    };
+
+    if piece.len() >= HEAP_THRESHOLD {
+        // Heap-driven selection: O(log N) per merge with no head-refinding walk.
+        // Each entry is `(rank, node_index, version)`; entries whose recorded
+        // version no longer matches the node (or which point at a consumed node)
+        // are stale and skipped.
+        let mut heap: BinaryHeap<Reverse<(u32, usize, u32)>> =
+            BinaryHeap::with_capacity(nodes.len());
+        for (i, node) in nodes.iter().enumerate() {
+            if node.rank != u32::MAX {
+                heap.push(Reverse((node.rank, i, node.version)));
+            }
+        }
+
+        while let Some(Reverse((rank, idx, version))) = heap.pop() {
+            // Skip stale or tombstoned entries.
+            if version != nodes[idx].version || nodes[idx].rank != rank {
+                continue;
+            }
+            if rank == u32::MAX || nodes[idx].next == usize::MAX {
+                continue;
+            }
+
+            let (prev, _new_next) = apply_merge(idx, &mut nodes);
+
+            // Push fresh entries for the two pairs whose ranks just changed.
+            if prev != usize::MAX && nodes[prev].rank != u32::MAX {
+                heap.push(Reverse((nodes[prev].rank, prev, nodes[prev].version)));
+            }
+            if nodes[idx].rank != u32::MAX {
+                heap.push(Reverse((nodes[idx].rank, idx, nodes[idx].version)));
+            }
+        }
+    } else {
+        // Linear min-rank scan: cheapest for short pieces.
+        loop {
+            // Find the pair with minimum rank (highest priority merge)
+            let mut min_rank = u32::MAX;
+            let mut min_idx = usize::MAX;
+
+            let mut curr = 0;
+            // Find the head of the list (in case we started from a deleted node)
+            while nodes[curr].prev != usize::MAX {
+                curr = nodes[curr].prev;
+            }
+
+            // Linear scan through the linked list
+            while curr != usize::MAX {
+                let r = nodes[curr].rank;
+                if r < min_rank {
+                    min_rank = r;
+                    min_idx = curr;
+                }
+                curr = nodes[curr].next;
+            }
+
+            // No more merges possible
+            if min_rank == u32::MAX {
+                break;
+            }
+
+            apply_merge(min_idx, &mut nodes);
+        }
     }
 
-    // Collect final tokens by traversing the linked list
-    let mut result = Vec::new();
+    // Collect final segments by traversing the linked list
+    let mut segments = Vec::new();
 
     // Find head
     let mut curr = 0;
@@ -177,23 +427,11 @@ pub fn byte_pair_encode(piece: &[u8], encoder: &FxHashMap<Vec<u8>, u32>) -> Vec<
 
     while curr != usize::MAX {
         let node = &nodes[curr];
-        let slice = &piece[node.start..node.start + node.len];
-
-        if let Some(&rank) = encoder.get(slice) {
-            result.push(rank);
-        } else {
-            // Fallback: if somehow we have an unknown token, try to encode bytes individually
-            // This shouldn't happen with a proper BPE vocabulary that covers all bytes
-            for &byte in slice {
-                if let Some(&rank) = encoder.get(&[byte][..]) {
-                    result.push(rank);
-                }
-            }
-        }
+        segments.push((node.start, node.len));
         curr = nodes[curr].next;
     }
 
-    result
+    segments
 }
 
 #[cfg(test)]
@@ -248,4 +486,54 @@ mod tests {
         // "ac" has no merge entry, so stays as [a, c]
         assert_eq!(byte_pair_encode(b"ac", &encoder), vec![0, 2]);
     }
+
+    #[test]
+    fn test_large_recursive_splits_on_natural_boundary() {
+        let encoder = make_encoder();
+        // With a small threshold the recursive path kicks in; the splits land on
+        // the unmergeable "ca" boundaries, so each "abc" still merges to 5.
+        assert_eq!(
+            byte_pair_encode_large(b"abcabcabc", &encoder, 4),
+            vec![5, 5, 5]
+        );
+        // Below the threshold it matches the plain encoder exactly.
+        assert_eq!(
+            byte_pair_encode_large(b"abc", &encoder, 4),
+            byte_pair_encode(b"abc", &encoder)
+        );
+    }
+
+    #[test]
+    fn test_batch_matches_serial() {
+        let encoder = make_encoder();
+        let pieces: Vec<&[u8]> = vec![b"abc", b"ab", b"ac", b"a", b""];
+        let batched = byte_pair_encode_batch(&pieces, &encoder);
+        let serial: Vec<Vec<u32>> = pieces
+            .iter()
+            .map(|p| byte_pair_encode(p, &encoder))
+            .collect();
+        assert_eq!(batched, serial);
+    }
+
+    #[test]
+    fn test_split_boundaries() {
+        let encoder = make_encoder();
+        // "abc" merges into a single piece.
+        assert_eq!(byte_pair_split(b"abc", &encoder), vec![&b"abc"[..]]);
+        // "ac" has no merge, so it stays split into its two bytes.
+        assert_eq!(
+            byte_pair_split(b"ac", &encoder),
+            vec![&b"a"[..], &b"c"[..]]
+        );
+    }
+
+    #[test]
+    fn test_heap_path_matches_linear() {
+        let encoder = make_encoder();
+        // A piece longer than HEAP_THRESHOLD exercises the heap selection path;
+        // it must produce the same greedy merge as the linear scan.
+        let piece = b"abc".repeat(HEAP_THRESHOLD); // 3 * 256 bytes
+        let expected = vec![5u32; HEAP_THRESHOLD];
+        assert_eq!(byte_pair_encode(&piece, &encoder), expected);
+    }
 }
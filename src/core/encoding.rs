@@ -0,0 +1,116 @@
+//! Top-level tokenizer built on top of the core BPE merge routine.
+//!
+//! [`byte_pair_encode`] only merges a single, already-split `piece`. Real
+//! tiktoken-style tokenization first splits raw text with a pattern regex
+//! (contractions, letter runs, number runs, whitespace) and only then runs BPE
+//! on each fragment. Splitting first also means each merge call sees one
+//! fragment rather than the whole text; the pattern itself does not cap how
+//! long a fragment can be.
+//!
+//! [`Encoding`] wires those two stages together: a compiled pattern plus the
+//! vocabulary map, with [`Encoding::encode_ordinary`] for text → ranks and
+//! [`Encoding::decode`] for ranks → bytes.
+
+use std::sync::OnceLock;
+
+use fancy_regex::Regex;
+use rustc_hash::FxHashMap;
+
+use super::bpe::byte_pair_encode;
+
+/// A pattern-driven tokenizer over a BPE vocabulary.
+///
+/// Holds the `encoder` map and a compiled splitting `pattern`. The reverse
+/// (rank → bytes) map used by [`decode`](Encoding::decode) is built lazily on
+/// first use and cached, since many callers only ever encode.
+pub struct Encoding {
+    /// Maps token bytes to their rank.
+    encoder: FxHashMap<Vec<u8>, u32>,
+    /// Pattern used to pre-tokenize raw text before BPE.
+    pattern: Regex,
+    /// Lazily-built inverse of `encoder`, populated on the first `decode` call.
+    decoder: OnceLock<FxHashMap<u32, Vec<u8>>>,
+}
+
+impl Encoding {
+    /// Build an encoding from a vocabulary and a pre-tokenization pattern.
+    ///
+    /// Returns an error if `pattern` is not a valid regex.
+    pub fn new(
+        encoder: FxHashMap<Vec<u8>, u32>,
+        pattern: &str,
+    ) -> Result<Self, Box<fancy_regex::Error>> {
+        Ok(Self {
+            encoder,
+            pattern: Regex::new(pattern).map_err(Box::new)?,
+            decoder: OnceLock::new(),
+        })
+    }
+
+    /// Encode raw text into token ranks, ignoring any special tokens.
+    ///
+    /// The text is first split into fragments by the pattern regex; each
+    /// fragment is then run through [`byte_pair_encode`] and the results are
+    /// concatenated in order.
+    pub fn encode_ordinary(&self, text: &str) -> Vec<u32> {
+        let mut result = Vec::new();
+        for m in self.pattern.find_iter(text) {
+            // `find_iter` only errors on regexes that can backtrack unboundedly;
+            // a match that fails to compute is treated as end-of-input.
+            let Ok(m) = m else { break };
+            result.extend(byte_pair_encode(m.as_str().as_bytes(), &self.encoder));
+        }
+        result
+    }
+
+    /// Decode token ranks back into the original bytes.
+    ///
+    /// Ranks with no entry in the vocabulary contribute nothing, mirroring the
+    /// lenient fallback in [`byte_pair_encode`].
+    pub fn decode(&self, tokens: &[u32]) -> Vec<u8> {
+        let decoder = self.decoder.get_or_init(|| {
+            self.encoder
+                .iter()
+                .map(|(bytes, &rank)| (rank, bytes.clone()))
+                .collect()
+        });
+
+        let mut result = Vec::new();
+        for token in tokens {
+            if let Some(bytes) = decoder.get(token) {
+                result.extend_from_slice(bytes);
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_encoding() -> Encoding {
+        let mut encoder = FxHashMap::default();
+        for (i, b) in (b'a'..=b'z').enumerate() {
+            encoder.insert(vec![b], i as u32);
+        }
+        encoder.insert(b" ".to_vec(), 26);
+        encoder.insert(b"ab".to_vec(), 27);
+        // Split on runs of letters or single other characters.
+        Encoding::new(encoder, r"\w+|[^\w]").unwrap()
+    }
+
+    #[test]
+    fn test_encode_ordinary_splits_then_merges() {
+        let enc = make_encoding();
+        // "ab c" -> ["ab", " ", "c"] by the pattern; "ab" merges to 27.
+        assert_eq!(enc.encode_ordinary("ab c"), vec![27, 26, 2]);
+    }
+
+    #[test]
+    fn test_decode_roundtrip() {
+        let enc = make_encoding();
+        let tokens = enc.encode_ordinary("ab c");
+        assert_eq!(enc.decode(&tokens), b"ab c".to_vec());
+    }
+}
@@ -0,0 +1,5 @@
+//! Core tokenization primitives: the BPE merge routine and the pattern-driven
+//! [`Encoding`](encoding::Encoding) tokenizer built on top of it.
+
+pub mod bpe;
+pub mod encoding;